@@ -0,0 +1,182 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use super::{
+    dead_letter::{DeadLetterSink, FileDeadLetterSink, DEFAULT_DEAD_LETTER_PATH},
+    feature_flag::DEFAULT_POLL_INTERVAL,
+    queue::{RetryConfig, DEFAULT_MAX_BATCH_BYTES, DEFAULT_REQUEST_TIMEOUT},
+    validation::{
+        ValidationConfig, DEFAULT_MAX_EVENT_BYTES, DEFAULT_MAX_PROPERTIES,
+        DEFAULT_MAX_PROPERTY_VALUE_BYTES,
+    },
+    PosthogClient,
+};
+
+const DEFAULT_BASE_URL: &str = "https://app.posthog.com";
+
+#[derive(Debug, Default)]
+pub struct PosthogClientBuilder {
+    base_url: Option<String>,
+    api_key: Option<String>,
+    max_retries: Option<u32>,
+    base_delay: Option<Duration>,
+    dead_letter_sink: Option<Arc<dyn DeadLetterSink>>,
+    feature_flag_poll_interval: Option<Duration>,
+    request_timeout: Option<Duration>,
+    max_batch_bytes: Option<usize>,
+    max_event_bytes: Option<usize>,
+    max_properties: Option<usize>,
+    max_property_value_bytes: Option<usize>,
+    allowed_event_names: Option<HashSet<String>>,
+    denied_event_names: Option<HashSet<String>>,
+}
+
+impl PosthogClientBuilder {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    pub fn base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = Some(base_url.into());
+        self
+    }
+
+    /// Maximum number of retry attempts for a `CaptureEvent`/`CaptureBatch`
+    /// request before it is handed to the dead-letter sink. Defaults to 3.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = Some(max_retries);
+        self
+    }
+
+    /// Base delay used for the retry backoff (`base_delay * 2^attempt`,
+    /// capped and jittered). Defaults to 500ms.
+    pub fn base_delay(mut self, base_delay: Duration) -> Self {
+        self.base_delay = Some(base_delay);
+        self
+    }
+
+    /// Where requests are sent once they've exhausted their retries.
+    /// Defaults to a [`FileDeadLetterSink`] writing to
+    /// `posthog-dead-letters.jsonl` in the working directory.
+    pub fn dead_letter_sink(mut self, sink: impl DeadLetterSink + 'static) -> Self {
+        self.dead_letter_sink = Some(Arc::new(sink));
+        self
+    }
+
+    /// How often cached feature flag definitions are refreshed from
+    /// `api/feature_flag/local_evaluation`. Defaults to 30s.
+    pub fn feature_flag_poll_interval(mut self, interval: Duration) -> Self {
+        self.feature_flag_poll_interval = Some(interval);
+        self
+    }
+
+    /// Upper bound on how long a single request is allowed to take before
+    /// it's treated as [`PosthogError::Timeout`][crate::PosthogError::Timeout].
+    /// Defaults to 10s.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Ceiling on the combined serialized size of the events in a single
+    /// `/batch` request (the request's own JSON wrapper is not counted).
+    /// Once accumulated events would exceed this, they're split across
+    /// multiple `CaptureBatch` requests instead. Defaults to 20MB.
+    pub fn max_batch_bytes(mut self, max_batch_bytes: usize) -> Self {
+        self.max_batch_bytes = Some(max_batch_bytes);
+        self
+    }
+
+    /// Maximum serialized size of a single event. Events over this are
+    /// rejected with [`PosthogError::Validation`][crate::PosthogError::Validation]
+    /// instead of being enqueued. Defaults to 1MB.
+    pub fn max_event_bytes(mut self, max_event_bytes: usize) -> Self {
+        self.max_event_bytes = Some(max_event_bytes);
+        self
+    }
+
+    /// Maximum number of properties a single event may carry. Defaults to
+    /// 1000.
+    pub fn max_properties(mut self, max_properties: usize) -> Self {
+        self.max_properties = Some(max_properties);
+        self
+    }
+
+    /// Maximum serialized size of a single property value. Defaults to
+    /// 64KB.
+    pub fn max_property_value_bytes(mut self, max_property_value_bytes: usize) -> Self {
+        self.max_property_value_bytes = Some(max_property_value_bytes);
+        self
+    }
+
+    /// If set, only events whose name is in `names` are accepted; every
+    /// other event is rejected with
+    /// [`PosthogError::Validation`][crate::PosthogError::Validation].
+    pub fn allow_event_names(
+        mut self,
+        names: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.allowed_event_names = Some(names.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Events whose name is in `names` are rejected with
+    /// [`PosthogError::Validation`][crate::PosthogError::Validation] instead
+    /// of being enqueued. Checked before the allowlist.
+    pub fn deny_event_names(
+        mut self,
+        names: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.denied_event_names = Some(names.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn build(self) -> PosthogClient {
+        let base_url = self
+            .base_url
+            .unwrap_or_else(|| DEFAULT_BASE_URL.to_string());
+        let api_key = self.api_key.expect("api_key is required");
+
+        let default_retry = RetryConfig::default();
+        let retry = RetryConfig {
+            max_retries: self.max_retries.unwrap_or(default_retry.max_retries),
+            base_delay: self.base_delay.unwrap_or(default_retry.base_delay),
+        };
+
+        let dead_letter_sink = self
+            .dead_letter_sink
+            .unwrap_or_else(|| Arc::new(FileDeadLetterSink::new(DEFAULT_DEAD_LETTER_PATH)));
+
+        let feature_flag_poll_interval = self
+            .feature_flag_poll_interval
+            .unwrap_or(DEFAULT_POLL_INTERVAL);
+
+        let request_timeout = self.request_timeout.unwrap_or(DEFAULT_REQUEST_TIMEOUT);
+        let max_batch_bytes = self.max_batch_bytes.unwrap_or(DEFAULT_MAX_BATCH_BYTES);
+
+        let validation = ValidationConfig {
+            max_event_bytes: self.max_event_bytes.unwrap_or(DEFAULT_MAX_EVENT_BYTES),
+            max_properties: self.max_properties.unwrap_or(DEFAULT_MAX_PROPERTIES),
+            max_property_value_bytes: self
+                .max_property_value_bytes
+                .unwrap_or(DEFAULT_MAX_PROPERTY_VALUE_BYTES),
+            allowed_event_names: self.allowed_event_names,
+            denied_event_names: self.denied_event_names,
+        };
+
+        PosthogClient::new(
+            base_url,
+            api_key,
+            retry,
+            dead_letter_sink,
+            feature_flag_poll_interval,
+            request_timeout,
+            max_batch_bytes,
+            validation,
+        )
+    }
+}
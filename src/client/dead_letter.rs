@@ -0,0 +1,104 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::queue::PosthogRequest;
+
+/// Default location for [`FileDeadLetterSink`] when the builder isn't given
+/// an explicit one.
+pub(crate) const DEFAULT_DEAD_LETTER_PATH: &str = "posthog-dead-letters.jsonl";
+
+/// Which kind of request a [`DeadLetterRequest`] was built from, so it can
+/// be turned back into a [`PosthogRequest`] on replay.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum DeadLetterKind {
+    CaptureEvent,
+    CaptureBatch,
+}
+
+/// A request that exhausted its retries, serialized so it can be written to
+/// a [`DeadLetterSink`] and later replayed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetterRequest {
+    pub kind: DeadLetterKind,
+    pub body: Value,
+}
+
+impl DeadLetterRequest {
+    pub(crate) fn from_request(kind: DeadLetterKind, body: Value) -> Self {
+        Self { kind, body }
+    }
+
+    /// Turn this record back into the `PosthogRequest` it was built from, so
+    /// it can be re-enqueued with the worker.
+    pub fn into_request(self) -> PosthogRequest {
+        match self.kind {
+            DeadLetterKind::CaptureEvent => PosthogRequest::CaptureEvent { body: self.body },
+            DeadLetterKind::CaptureBatch => PosthogRequest::CaptureBatch { body: self.body },
+        }
+    }
+}
+
+/// Pluggable destination for requests that failed every retry attempt.
+///
+/// Implementations should not panic - a dead-letter write is already the
+/// last resort after a request has failed, and panicking would take the
+/// whole worker down with it.
+#[async_trait::async_trait]
+pub trait DeadLetterSink: std::fmt::Debug + Send + Sync {
+    async fn write(&self, request: DeadLetterRequest);
+}
+
+/// Default [`DeadLetterSink`] that appends each record as a line of JSON to
+/// a file on disk, so it can be replayed into a freshly started worker with
+/// [`FileDeadLetterSink::read_all`].
+#[derive(Debug, Clone)]
+pub struct FileDeadLetterSink {
+    path: PathBuf,
+}
+
+impl FileDeadLetterSink {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Read back every record previously written to this sink's file.
+    /// Lines that fail to parse (e.g. a partial write) are skipped.
+    pub async fn read_all(&self) -> std::io::Result<Vec<DeadLetterRequest>> {
+        let contents = match tokio::fs::read_to_string(&self.path).await {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+
+        Ok(contents
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect())
+    }
+}
+
+#[async_trait::async_trait]
+impl DeadLetterSink for FileDeadLetterSink {
+    async fn write(&self, request: DeadLetterRequest) {
+        use tokio::io::AsyncWriteExt;
+
+        let Ok(mut line) = serde_json::to_vec(&request) else {
+            return;
+        };
+        line.push(b'\n');
+
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await;
+
+        if let Ok(mut file) = file {
+            file.write_all(&line).await.ok();
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use std::collections::HashMap;
+
+use serde_json::{json, Value};
+
+use crate::error::PosthogError;
+
+use super::{
+    queue::{PosthogRequest, QueuedRequest},
+    PosthogClient,
+};
+
+impl PosthogClient {
+    /// Capture an event for `distinct_id`. The event is validated against
+    /// the configured payload limits (see
+    /// [`PosthogClientBuilder`][crate::PosthogClientBuilder]) and, if it
+    /// passes, handed to the queue worker for batching and delivery.
+    ///
+    /// Returns [`PosthogError::Validation`] without enqueueing anything if
+    /// the event violates a configured limit.
+    pub fn capture(
+        &self,
+        event: impl Into<String>,
+        distinct_id: impl Into<String>,
+        properties: HashMap<String, Value>,
+    ) -> Result<(), PosthogError> {
+        let event = event.into();
+        let body = json!({
+            "api_key": self.api_key,
+            "event": event,
+            "distinct_id": distinct_id.into(),
+            "properties": properties,
+        });
+
+        self.validation.validate(&event, &body)?;
+
+        self.worker.enqueue(QueuedRequest {
+            request: PosthogRequest::CaptureEvent { body },
+            response_tx: None,
+            attempts: 0,
+        });
+
+        Ok(())
+    }
+}
@@ -0,0 +1,407 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::Value;
+use sha1::{Digest, Sha1};
+use tokio::sync::RwLock;
+
+use crate::error::PosthogError;
+
+/// Denominator used to turn the first 15 hex characters of a sha1 digest
+/// into a float in `[0, 1)`, per PostHog's deterministic rollout algorithm.
+const ROLLOUT_DIVISOR: u64 = 0xfffffffffffffff;
+
+pub(crate) const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The result of trying to evaluate a flag against the locally cached
+/// definitions.
+#[derive(Debug)]
+pub(crate) enum FeatureFlagDecision {
+    Enabled(FlagValue),
+    Disabled,
+    /// The flag couldn't be evaluated locally (e.g. it references a
+    /// cohort), so the caller should fall back to `/decide`.
+    Unknown,
+}
+
+/// The resolved value of a feature flag: either a plain on/off boolean, or
+/// the key of the variant a multivariate flag resolved to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FlagValue {
+    Boolean(bool),
+    Variant(String),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct LocalEvaluationResponse {
+    #[serde(default)]
+    flags: Vec<FlagDefinition>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct FlagDefinition {
+    key: String,
+    #[serde(default = "default_active")]
+    active: bool,
+    filters: FlagFilters,
+}
+
+fn default_active() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct FlagFilters {
+    #[serde(default)]
+    groups: Vec<FlagGroup>,
+    #[serde(default)]
+    multivariate: Option<Multivariate>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct FlagGroup {
+    #[serde(default)]
+    properties: Vec<PropertyFilter>,
+    rollout_percentage: Option<f64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct PropertyFilter {
+    key: String,
+    value: Value,
+    #[serde(default)]
+    operator: Operator,
+    #[serde(default, rename = "type")]
+    kind: PropertyType,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Operator {
+    #[default]
+    Exact,
+    Icontains,
+    Gt,
+    Lt,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum PropertyType {
+    #[default]
+    Person,
+    Group,
+    Cohort,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Multivariate {
+    variants: Vec<Variant>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Variant {
+    key: String,
+    rollout_percentage: f64,
+}
+
+/// Caches flag definitions fetched from `local_evaluation` and evaluates
+/// them in-process, so a hot path doesn't need a `/decide` round trip for
+/// every flag check.
+#[derive(Debug)]
+pub(crate) struct LocalFeatureFlags {
+    definitions: Arc<RwLock<Vec<FlagDefinition>>>,
+}
+
+impl LocalFeatureFlags {
+    /// Spawns a background task that fetches flag definitions immediately
+    /// and then every `poll_interval`, and returns a handle that can
+    /// evaluate flags against whatever was last fetched.
+    pub(crate) fn start(
+        client: Client,
+        base_url: String,
+        api_key: String,
+        poll_interval: Duration,
+    ) -> Self {
+        let definitions: Arc<RwLock<Vec<FlagDefinition>>> = Arc::new(RwLock::new(Vec::new()));
+        let poll_definitions = definitions.clone();
+
+        tokio::spawn(async move {
+            loop {
+                if let Ok(flags) = fetch_definitions(&client, &base_url, &api_key).await {
+                    *poll_definitions.write().await = flags;
+                }
+                tokio::time::sleep(poll_interval).await;
+            }
+        });
+
+        Self { definitions }
+    }
+
+    pub(crate) async fn evaluate(
+        &self,
+        flag_key: &str,
+        distinct_id: &str,
+        properties: &HashMap<String, Value>,
+    ) -> FeatureFlagDecision {
+        let definitions = self.definitions.read().await;
+        match definitions.iter().find(|flag| flag.key == flag_key) {
+            Some(flag) => evaluate_flag(flag, distinct_id, properties),
+            // We have no definition for this flag at all (either it
+            // doesn't exist or we haven't fetched yet) - let the caller
+            // fall back to `/decide` rather than reporting it disabled.
+            None => FeatureFlagDecision::Unknown,
+        }
+    }
+}
+
+async fn fetch_definitions(
+    client: &Client,
+    base_url: &str,
+    api_key: &str,
+) -> Result<Vec<FlagDefinition>, PosthogError> {
+    let url = format!("{base_url}/api/feature_flag/local_evaluation?token={api_key}");
+
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .map_err(PosthogError::HttpError)?
+        .error_for_status()
+        .map_err(PosthogError::HttpError)?;
+
+    let parsed: LocalEvaluationResponse =
+        response.json().await.map_err(PosthogError::HttpError)?;
+
+    Ok(parsed.flags)
+}
+
+fn evaluate_flag(
+    flag: &FlagDefinition,
+    distinct_id: &str,
+    properties: &HashMap<String, Value>,
+) -> FeatureFlagDecision {
+    if !flag.active {
+        return FeatureFlagDecision::Disabled;
+    }
+
+    for group in &flag.filters.groups {
+        match group_matches(group, properties) {
+            None => return FeatureFlagDecision::Unknown,
+            Some(false) => continue,
+            Some(true) => {}
+        }
+
+        let in_rollout = match group.rollout_percentage {
+            Some(percentage) => rollout_hash(&flag.key, distinct_id, "") <= percentage / 100.0,
+            None => true,
+        };
+
+        if !in_rollout {
+            continue;
+        }
+
+        return FeatureFlagDecision::Enabled(match &flag.filters.multivariate {
+            Some(multivariate) => FlagValue::Variant(pick_variant(
+                &flag.key,
+                distinct_id,
+                multivariate,
+            )),
+            None => FlagValue::Boolean(true),
+        });
+    }
+
+    FeatureFlagDecision::Disabled
+}
+
+/// `None` means a filter in this group couldn't be evaluated locally (e.g.
+/// a cohort) and the flag as a whole needs to fall back to `/decide`.
+fn group_matches(group: &FlagGroup, properties: &HashMap<String, Value>) -> Option<bool> {
+    for filter in &group.properties {
+        match property_matches(filter, properties)? {
+            true => continue,
+            false => return Some(false),
+        }
+    }
+    Some(true)
+}
+
+fn property_matches(filter: &PropertyFilter, properties: &HashMap<String, Value>) -> Option<bool> {
+    if filter.kind == PropertyType::Cohort {
+        return None;
+    }
+
+    // The caller didn't supply a property this filter references - we can't
+    // tell locally whether PostHog would see it (e.g. it may be a person
+    // property set server-side), so fall back to `/decide` rather than
+    // assuming the group doesn't match.
+    let actual = properties.get(&filter.key)?;
+
+    Some(match filter.operator {
+        // `local_evaluation` almost always delivers `value` as an array
+        // (even for a single value, e.g. `["US"]") - match against any
+        // element rather than the array as a whole.
+        Operator::Exact => match &filter.value {
+            Value::Array(expected) => expected.iter().any(|item| values_equal(actual, item)),
+            expected => values_equal(actual, expected),
+        },
+        Operator::Icontains => {
+            let haystack = value_to_string(actual).to_lowercase();
+            match &filter.value {
+                Value::Array(expected) => expected
+                    .iter()
+                    .any(|item| haystack.contains(&value_to_string(item).to_lowercase())),
+                expected => haystack.contains(&value_to_string(expected).to_lowercase()),
+            }
+        }
+        Operator::Gt => compare_numbers(actual, &filter.value).is_some_and(|(a, b)| a > b),
+        Operator::Lt => compare_numbers(actual, &filter.value).is_some_and(|(a, b)| a < b),
+    })
+}
+
+fn values_equal(actual: &Value, expected: &Value) -> bool {
+    match (actual, expected) {
+        (Value::String(a), Value::String(b)) => a == b,
+        (Value::Number(_), Value::Number(_)) => actual.as_f64() == expected.as_f64(),
+        _ => actual == expected,
+    }
+}
+
+fn compare_numbers(actual: &Value, expected: &Value) -> Option<(f64, f64)> {
+    let to_f64 = |value: &Value| {
+        value
+            .as_f64()
+            .or_else(|| value.as_str().and_then(|s| s.parse().ok()))
+    };
+    Some((to_f64(actual)?, to_f64(expected)?))
+}
+
+fn value_to_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// PostHog's deterministic rollout hash: `sha1("{flag_key}.{distinct_id}{salt}")`
+/// (the salt, e.g. `"variant"` for multivariate bucketing, is appended with
+/// no separator), taking the first 15 hex characters as an integer and
+/// normalizing to `[0, 1)`.
+fn rollout_hash(flag_key: &str, distinct_id: &str, salt: &str) -> f64 {
+    let input = format!("{flag_key}.{distinct_id}{salt}");
+
+    let digest = Sha1::digest(input.as_bytes());
+    let hex: String = digest.iter().map(|byte| format!("{byte:02x}")).collect();
+    let value = u64::from_str_radix(&hex[..15], 16).unwrap();
+
+    value as f64 / ROLLOUT_DIVISOR as f64
+}
+
+/// Walks a multivariate flag's variants in order, accumulating their
+/// rollout percentages, and returns the one the salted hash falls into.
+fn pick_variant(flag_key: &str, distinct_id: &str, multivariate: &Multivariate) -> String {
+    let hash = rollout_hash(flag_key, distinct_id, "variant");
+
+    let mut cumulative = 0.0;
+    for variant in &multivariate.variants {
+        cumulative += variant.rollout_percentage / 100.0;
+        if hash < cumulative {
+            return variant.key.clone();
+        }
+    }
+
+    multivariate
+        .variants
+        .last()
+        .map(|variant| variant.key.clone())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    // Pinned against an independent `sha1(f"{key}.{distinct_id}{salt}")[:15]
+    // / 0xfffffffffffffff` computation of PostHog's documented rollout
+    // algorithm, so a change to the hash input (e.g. reintroducing a
+    // separator before the salt) is caught immediately.
+    #[test]
+    fn rollout_hash_matches_known_vectors() {
+        assert!((rollout_hash("test-flag", "user-1", "") - 0.007_041_759_849_595_705).abs() < 1e-12);
+        assert!(
+            (rollout_hash("test-flag", "user-1", "variant") - 0.565_789_683_549_194_7).abs()
+                < 1e-12
+        );
+        assert!(
+            (rollout_hash("beta-feature", "distinct_id_1", "") - 0.993_334_458_908_969_8).abs()
+                < 1e-12
+        );
+    }
+
+    #[test]
+    fn pick_variant_picks_first_variant_whose_cumulative_boundary_contains_the_hash() {
+        // rollout_hash("test-flag", "user-1", "variant") ~= 0.5658
+        let multivariate = Multivariate {
+            variants: vec![
+                Variant {
+                    key: "control".to_string(),
+                    rollout_percentage: 50.0,
+                },
+                Variant {
+                    key: "test".to_string(),
+                    rollout_percentage: 50.0,
+                },
+            ],
+        };
+
+        assert_eq!(
+            pick_variant("test-flag", "user-1", &multivariate),
+            "test"
+        );
+    }
+
+    #[test]
+    fn pick_variant_falls_back_to_last_variant_when_percentages_dont_cover_the_hash() {
+        // rollout_hash("test-flag", "user-1", "variant") ~= 0.5658, which is
+        // past the 10% covered by the one variant below.
+        let multivariate = Multivariate {
+            variants: vec![Variant {
+                key: "only".to_string(),
+                rollout_percentage: 10.0,
+            }],
+        };
+
+        assert_eq!(pick_variant("test-flag", "user-1", &multivariate), "only");
+    }
+
+    #[test]
+    fn property_matches_exact_against_array_value() {
+        let filter = PropertyFilter {
+            key: "country".to_string(),
+            value: json!(["US"]),
+            operator: Operator::Exact,
+            kind: PropertyType::Person,
+        };
+        let mut properties = HashMap::new();
+        properties.insert("country".to_string(), json!("US"));
+
+        assert_eq!(property_matches(&filter, &properties), Some(true));
+    }
+
+    #[test]
+    fn property_matches_icontains_against_array_value() {
+        let filter = PropertyFilter {
+            key: "email".to_string(),
+            value: json!(["@posthog.com", "@example.com"]),
+            operator: Operator::Icontains,
+            kind: PropertyType::Person,
+        };
+        let mut properties = HashMap::new();
+        properties.insert("email".to_string(), json!("person@example.com"));
+
+        assert_eq!(property_matches(&filter, &properties), Some(true));
+    }
+}
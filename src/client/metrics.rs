@@ -0,0 +1,46 @@
+//! Thin facade over the optional `metrics` crate so the rest of the worker
+//! can record counters/gauges unconditionally - these all compile away to
+//! nothing when the `metrics` feature is disabled.
+
+#[cfg(feature = "metrics")]
+pub(crate) fn event_enqueued() {
+    metrics::counter!("posthog_events_enqueued_total").increment(1);
+}
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn event_enqueued() {}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn batch_flushed(size: usize) {
+    metrics::counter!("posthog_batches_flushed_total").increment(1);
+    metrics::histogram!("posthog_batch_size").record(size as f64);
+}
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn batch_flushed(_size: usize) {}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn queue_depth(depth: usize) {
+    metrics::gauge!("posthog_queue_depth").set(depth as f64);
+}
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn queue_depth(_depth: usize) {}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn in_flight_requests(count: usize) {
+    metrics::gauge!("posthog_in_flight_requests").set(count as f64);
+}
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn in_flight_requests(_count: usize) {}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn request_retried() {
+    metrics::counter!("posthog_request_retries_total").increment(1);
+}
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn request_retried() {}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn request_failed() {
+    metrics::counter!("posthog_request_failures_total").increment(1);
+}
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn request_failed() {}
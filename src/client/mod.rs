@@ -1,26 +1,46 @@
 mod builder;
+mod dead_letter;
 mod early_access;
 mod event;
 mod feature_flag;
 mod identify;
+mod metrics;
 mod queue;
+mod validation;
 mod view;
 
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
 pub use builder::PosthogClientBuilder;
-use tokio::sync::mpsc;
+pub use dead_letter::{DeadLetterRequest, DeadLetterSink, FileDeadLetterSink};
+pub use feature_flag::FlagValue;
+use reqwest::Client;
+use serde_json::{json, Value};
+use tokio::sync::oneshot;
+
+use crate::error::PosthogError;
 
-use self::queue::{QueueWorker, QueuedRequest};
+use self::{
+    feature_flag::{FeatureFlagDecision, LocalFeatureFlags},
+    queue::{PosthogRequest, QueueWorker, QueuedRequest, RetryConfig},
+    validation::ValidationConfig,
+};
 
 const POSTHOG_BATCH_LIMIT: i64 = 20; // TODO(colemickens): revisit check posthog docs
 
 #[derive(Debug, Clone)]
 pub struct PosthogClient {
     pub(crate) api_key: String,
+    pub(crate) base_url: String,
 
     // NOTE(colemickens): move this to PosthogClient so that owner can
     // drop this. If its in QueueWorker, it gets cloned into the spawned thread
     // and prevents clean shutdown.
     pub(crate) worker: QueueWorker,
+
+    feature_flags: Arc<LocalFeatureFlags>,
+
+    validation: Arc<ValidationConfig>,
 }
 
 impl PosthogClient {
@@ -28,12 +48,119 @@ impl PosthogClient {
         PosthogClientBuilder::new()
     }
 
-    pub(crate) fn new(base_url: String, api_key: String) -> Self {
-        let worker = QueueWorker::start(base_url);
+    pub(crate) fn new(
+        base_url: String,
+        api_key: String,
+        retry: RetryConfig,
+        dead_letter: Arc<dyn DeadLetterSink>,
+        feature_flag_poll_interval: Duration,
+        request_timeout: Duration,
+        max_batch_bytes: usize,
+        validation: ValidationConfig,
+    ) -> Self {
+        let feature_flags = Arc::new(LocalFeatureFlags::start(
+            Client::new(),
+            base_url.clone(),
+            api_key.clone(),
+            feature_flag_poll_interval,
+        ));
+        let worker = QueueWorker::start(
+            base_url.clone(),
+            retry,
+            dead_letter,
+            request_timeout,
+            max_batch_bytes,
+        );
         let client = Self {
             api_key,
+            base_url,
             worker,
+            feature_flags,
+            validation: Arc::new(validation),
         };
         client
     }
+
+    /// Resolve a feature flag for `distinct_id`, evaluating against locally
+    /// cached flag definitions when possible and falling back to `/decide`
+    /// when the flag references data (e.g. a cohort) that isn't available
+    /// locally.
+    pub async fn get_feature_flag(
+        &self,
+        flag_key: &str,
+        distinct_id: &str,
+        properties: HashMap<String, Value>,
+    ) -> Result<FlagValue, PosthogError> {
+        match self
+            .feature_flags
+            .evaluate(flag_key, distinct_id, &properties)
+            .await
+        {
+            FeatureFlagDecision::Enabled(value) => Ok(value),
+            FeatureFlagDecision::Disabled => Ok(FlagValue::Boolean(false)),
+            FeatureFlagDecision::Unknown => {
+                self.evaluate_feature_flag_remote(flag_key, distinct_id, properties)
+                    .await
+            }
+        }
+    }
+
+    async fn evaluate_feature_flag_remote(
+        &self,
+        flag_key: &str,
+        distinct_id: &str,
+        properties: HashMap<String, Value>,
+    ) -> Result<FlagValue, PosthogError> {
+        let body = json!({
+            "api_key": self.api_key,
+            "distinct_id": distinct_id,
+            "person_properties": properties,
+        });
+
+        let (response_tx, response_rx) = oneshot::channel();
+        self.worker.dispatch_request(QueuedRequest {
+            request: PosthogRequest::EvaluateFeatureFlags { body },
+            response_tx: Some(response_tx),
+            attempts: 0,
+        });
+
+        let response = response_rx.await.map_err(|_| PosthogError::WorkerGone)??;
+
+        Ok(match &response["featureFlags"][flag_key] {
+            Value::String(variant) => FlagValue::Variant(variant.clone()),
+            Value::Bool(enabled) => FlagValue::Boolean(*enabled),
+            _ => FlagValue::Boolean(false),
+        })
+    }
+
+    /// Re-enqueue records previously written to a [`DeadLetterSink`], e.g.
+    /// read back via [`FileDeadLetterSink::read_all`] at startup, so they
+    /// get another chance at delivery.
+    pub async fn replay_dead_letters(&self, records: Vec<DeadLetterRequest>) {
+        for record in records {
+            self.worker.enqueue(QueuedRequest {
+                request: record.into_request(),
+                response_tx: None,
+                attempts: 0,
+            });
+        }
+    }
+
+    /// Force any events buffered in the queue worker to be sent immediately
+    /// and wait for all in-flight requests to complete.
+    ///
+    /// Unlike [`PosthogClient::shutdown`], this does not stop the worker from
+    /// accepting further events afterwards.
+    pub async fn flush(&self) {
+        self.worker.flush().await;
+    }
+
+    /// Stop the queue worker from accepting new events, flush anything still
+    /// buffered, and wait for all in-flight requests to complete.
+    ///
+    /// Call this before a short-lived process exits to guarantee that every
+    /// event captured so far has actually been sent.
+    pub async fn shutdown(self) {
+        self.worker.shutdown().await;
+    }
 }
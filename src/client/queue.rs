@@ -1,11 +1,46 @@
-use reqwest::{Client, Method};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use rand::Rng;
+use reqwest::{header::RETRY_AFTER, Client, Method, Response, StatusCode};
 use serde_json::{json, Value};
-use tokio::sync::{
-    mpsc::{self, unbounded_channel},
-    oneshot::Sender,
+use tokio::{
+    sync::{
+        mpsc::{self, unbounded_channel},
+        oneshot::{self, Sender},
+        Notify,
+    },
+    time::Instant,
 };
+use tracing::Instrument;
 
-use crate::{client::POSTHOG_BATCH_LIMIT, error::PosthogError};
+use crate::{
+    client::{
+        dead_letter::{DeadLetterKind, DeadLetterRequest, DeadLetterSink},
+        metrics, POSTHOG_BATCH_LIMIT,
+    },
+    error::PosthogError,
+};
+
+/// Upper bound on the retry backoff, regardless of how many attempts have
+/// been made or how large `base_delay` is configured.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Default ceiling on how long a single request is allowed to take before
+/// it's treated as [`PosthogError::Timeout`].
+pub(crate) const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default ceiling on the combined serialized size of the events in a single
+/// `/batch` request, matching PostHog's own limit on that endpoint. `POSTHOG_BATCH_LIMIT`
+/// bounds how many events we try to gather per tick, but a handful of
+/// large events can still add up to more bytes than PostHog will accept in
+/// one request, so `flush_batch` also splits on this.
+pub(crate) const DEFAULT_MAX_BATCH_BYTES: usize = 20 * 1024 * 1024;
 
 #[derive(Debug)]
 pub enum PosthogRequest {
@@ -59,68 +94,219 @@ pub(crate) struct QueuedRequest {
     pub(crate) request: PosthogRequest,
 
     pub(crate) response_tx: Option<Sender<Result<Value, PosthogError>>>,
+
+    /// How many times this request has already been attempted. Only
+    /// incremented for requests that go through the retry path.
+    pub(crate) attempts: u32,
+}
+
+/// Retry policy for `CaptureEvent`/`CaptureBatch` requests that fail with a
+/// transient error. Delay for a given attempt is `base_delay * 2^attempt`,
+/// capped at [`MAX_RETRY_DELAY`], with +/-20% jitter.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RetryConfig {
+    pub(crate) max_retries: u32,
+    pub(crate) base_delay: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay: Duration::from_millis(500),
+        }
+    }
+}
+
+fn retry_delay(config: &RetryConfig, attempt: u32) -> Duration {
+    let backoff = config.base_delay.saturating_mul(1u32 << attempt.min(16));
+    let capped = backoff.min(MAX_RETRY_DELAY);
+
+    let jitter = rand::thread_rng().gen_range(-0.2..=0.2);
+    capped.mul_f64((1.0 + jitter).max(0.0))
+}
+
+/// Messages accepted by the worker's channel. `QueuedRequest`s are wrapped
+/// alongside the control messages so that `flush`/`shutdown` are ordered
+/// relative to whatever was already enqueued ahead of them.
+#[derive(Debug)]
+enum WorkerMessage {
+    Enqueue(QueuedRequest),
+    Flush(Sender<()>),
+    Shutdown(Sender<()>),
+}
+
+/// Tracks the number of requests currently in flight outside the batch loop
+/// (those spawned by [`QueueWorkerHandle::dispatch_request`] and scheduled
+/// retries), so that `flush`/`shutdown` can wait for them all to complete.
+/// Requests the batch loop sends inline are not counted here; they have
+/// already finished by the time the loop acknowledges a flush or shutdown.
+#[derive(Clone, Debug, Default)]
+struct InFlightTracker {
+    count: Arc<AtomicUsize>,
+    notify: Arc<Notify>,
+}
+
+impl InFlightTracker {
+    fn enter(&self) -> InFlightGuard {
+        let count = self.count.fetch_add(1, Ordering::SeqCst) + 1;
+        metrics::in_flight_requests(count);
+        InFlightGuard {
+            tracker: self.clone(),
+        }
+    }
+
+    async fn wait_for_idle(&self) {
+        loop {
+            let notified = self.notify.notified();
+            if self.count.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+struct InFlightGuard {
+    tracker: InFlightTracker,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        let previous = self.tracker.count.fetch_sub(1, Ordering::SeqCst);
+        metrics::in_flight_requests(previous - 1);
+        if previous == 1 {
+            self.tracker.notify.notify_waiters();
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub(crate) struct QueueWorkerHandle {
-    pub(crate) tx: mpsc::UnboundedSender<QueuedRequest>,
+    tx: mpsc::UnboundedSender<WorkerMessage>,
     // extra default inner client used for immediate requests
     pub(crate) inner_client: QueueWorkerInner,
+    in_flight: InFlightTracker,
 }
 
-#[derive(Clone, Debug)]
+/// Alias kept for callers that think of the handle as "the worker" -
+/// the handle is the only thing they ever hold onto.
+pub(crate) type QueueWorker = QueueWorkerHandle;
+
+#[derive(Clone)]
 pub(crate) struct QueueWorkerInner {
     pub base_url: String,
     pub client: Client,
+    retry: RetryConfig,
+    dead_letter: Arc<dyn DeadLetterSink>,
+    // Shared "not before" gate set by a 429/503 Retry-After response, so a
+    // throttle applies to both the batching loop and `dispatch_request`'s
+    // immediate requests instead of just the request that got throttled.
+    rate_limit_gate: Arc<Mutex<Instant>>,
+    request_timeout: Duration,
+    max_batch_bytes: usize,
+    // Held by a scheduled retry for as long as it's sleeping/re-sending, so
+    // `flush`/`shutdown` wait for retries in flight instead of dropping them.
+    in_flight: InFlightTracker,
+}
+
+/// Outcome of a single HTTP round trip in [`QueueWorkerInner::send_once`].
+enum SendOutcome {
+    Success(Value),
+    RateLimited,
+}
+
+impl std::fmt::Debug for QueueWorkerInner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QueueWorkerInner")
+            .field("base_url", &self.base_url)
+            .field("retry", &self.retry)
+            .finish_non_exhaustive()
+    }
 }
 
 impl QueueWorkerHandle {
-    pub(crate) fn start(base_url: String) -> QueueWorkerHandle {
-        let (tx, mut rx) = unbounded_channel::<QueuedRequest>();
+    pub(crate) fn start(
+        base_url: String,
+        retry: RetryConfig,
+        dead_letter: Arc<dyn DeadLetterSink>,
+        request_timeout: Duration,
+        max_batch_bytes: usize,
+    ) -> QueueWorkerHandle {
+        let (tx, mut rx) = unbounded_channel::<WorkerMessage>();
+        let in_flight = InFlightTracker::default();
 
         let worker = QueueWorkerInner {
             base_url,
             client: Client::new(),
+            retry,
+            dead_letter,
+            rate_limit_gate: Arc::new(Mutex::new(Instant::now())),
+            request_timeout,
+            max_batch_bytes,
+            in_flight: in_flight.clone(),
         };
         let immediate_worker = worker.clone();
 
         tokio::spawn(async move {
             loop {
-                let mut buffer: Vec<QueuedRequest> = Vec::new();
-                let x = rx.recv_many(&mut buffer, POSTHOG_BATCH_LIMIT).await;
-                if x == 0 {
+                let mut buffer: Vec<WorkerMessage> = Vec::new();
+                let received = rx.recv_many(&mut buffer, POSTHOG_BATCH_LIMIT).await;
+                if received == 0 {
                     return;
                 }
 
-                let mut batch_capture = Vec::new();
+                metrics::queue_depth(buffer.len());
 
-                for request in buffer.into_iter() {
-                    match request.request {
-                        PosthogRequest::CaptureEvent { body } if request.response_tx.is_none() => {
-                            batch_capture.push(body);
-                        }
+                let span = tracing::info_span!("process_batch", messages = received);
+                let stop = async {
+                    let mut batch_capture = Vec::new();
+                    let mut flush_acks: Vec<Sender<()>> = Vec::new();
+                    let mut shutdown_ack = None;
 
-                        _ => {
-                            worker.handle_request(request).await;
+                    for message in buffer.into_iter() {
+                        match message {
+                            WorkerMessage::Enqueue(request) => match request.request {
+                                PosthogRequest::CaptureEvent { body }
+                                    if request.response_tx.is_none() =>
+                                {
+                                    batch_capture.push(body);
+                                }
+
+                                _ => {
+                                    worker.handle_request_with_retry(request).await;
+                                }
+                            },
+
+                            WorkerMessage::Flush(ack) => flush_acks.push(ack),
+
+                            WorkerMessage::Shutdown(ack) => {
+                                // Stop draining the buffer - anything queued
+                                // behind a shutdown request doesn't get sent.
+                                shutdown_ack = Some(ack);
+                                break;
+                            }
                         }
                     }
-                }
 
-                if !batch_capture.is_empty() {
-                    // The API key is added by the client to each event, so we can just take it from the first event.
-                    let api_key = batch_capture[0]["api_key"].as_str().unwrap();
+                    flush_batch(&worker, &mut batch_capture).await;
 
-                    let body = json!({
-                       "api_key": api_key,
-                       "batch": batch_capture,
-                    });
+                    for ack in flush_acks {
+                        ack.send(()).ok();
+                    }
 
-                    let request = QueuedRequest {
-                        request: PosthogRequest::CaptureBatch { body },
-                        response_tx: None,
-                    };
+                    if let Some(ack) = shutdown_ack {
+                        ack.send(()).ok();
+                        return true;
+                    }
 
-                    worker.handle_request(request).await;
+                    false
+                }
+                .instrument(span)
+                .await;
+
+                if stop {
+                    return;
                 }
             }
         });
@@ -128,17 +314,180 @@ impl QueueWorkerHandle {
         QueueWorkerHandle {
             tx,
             inner_client: immediate_worker,
+            in_flight,
         }
     }
 
+    pub(crate) fn enqueue(&self, request: QueuedRequest) {
+        metrics::event_enqueued();
+        self.tx.send(WorkerMessage::Enqueue(request)).ok();
+    }
+
     pub fn dispatch_request(&self, request: QueuedRequest) {
+        metrics::event_enqueued();
         let worker = self.inner_client.clone();
+        let guard = self.in_flight.enter();
         tokio::spawn(async move {
-            worker.handle_request(request).await;
+            worker.handle_request_with_retry(request).await;
+            drop(guard);
         });
     }
+
+    /// Force the currently buffered batch to be sent and wait for every
+    /// in-flight request - queued or dispatched immediately - to complete.
+    pub(crate) async fn flush(&self) {
+        let (ack_tx, ack_rx) = oneshot::channel();
+        if self.tx.send(WorkerMessage::Flush(ack_tx)).is_ok() {
+            ack_rx.await.ok();
+        }
+        self.in_flight.wait_for_idle().await;
+    }
+
+    /// Stop accepting new events, flush whatever is buffered, and wait for
+    /// every in-flight request to complete before returning.
+    pub(crate) async fn shutdown(self) {
+        let (ack_tx, ack_rx) = oneshot::channel();
+        if self.tx.send(WorkerMessage::Shutdown(ack_tx)).is_ok() {
+            ack_rx.await.ok();
+        }
+        self.in_flight.wait_for_idle().await;
+    }
+}
+
+/// Builds `/batch` requests out of whatever events have accumulated,
+/// splitting them by [`QueueWorkerInner::max_batch_bytes`] so a handful of
+/// large events can't produce a request bigger than PostHog will accept,
+/// and sends each one, leaving `batch_capture` empty.
+async fn flush_batch(worker: &QueueWorkerInner, batch_capture: &mut Vec<Value>) {
+    if batch_capture.is_empty() {
+        return;
+    }
+
+    // The API key is added by the client to each event, so we can just take it from the first event.
+    let api_key = batch_capture[0]["api_key"].as_str().unwrap().to_string();
+
+    for chunk in split_by_byte_size(std::mem::take(batch_capture), worker.max_batch_bytes) {
+        metrics::batch_flushed(chunk.len());
+
+        let body = json!({
+           "api_key": api_key,
+           "batch": chunk,
+        });
+
+        let request = QueuedRequest {
+            request: PosthogRequest::CaptureBatch { body },
+            response_tx: None,
+            attempts: 0,
+        };
+
+        worker.handle_request_with_retry(request).await;
+    }
 }
+
+/// Greedily groups `events` into chunks whose events' combined serialized
+/// size stays within `max_bytes`. Only each event's own JSON is counted; the
+/// array separators and the enclosing request body are not. An event that's already over the limit on its own still
+/// gets sent as a singleton chunk rather than silently dropped - PostHog can
+/// make that call when it rejects the request.
+fn split_by_byte_size(events: Vec<Value>, max_bytes: usize) -> Vec<Vec<Value>> {
+    let mut chunks = Vec::new();
+    let mut current = Vec::new();
+    let mut current_bytes = 0usize;
+
+    for event in events {
+        let event_bytes = serde_json::to_vec(&event).map(|bytes| bytes.len()).unwrap_or(0);
+
+        if !current.is_empty() && current_bytes + event_bytes > max_bytes {
+            chunks.push(std::mem::take(&mut current));
+            current_bytes = 0;
+        }
+
+        current_bytes += event_bytes;
+        current.push(event);
+    }
+
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+
+    chunks
+}
+
 impl QueueWorkerInner {
+    /// Handles a request, retrying `CaptureEvent`/`CaptureBatch` requests
+    /// that have no caller waiting on a response (i.e. went through the
+    /// queue, not [`QueueWorkerHandle::dispatch_request`] with an ack) on
+    /// failure, up to `retry.max_retries` times. Once retries are
+    /// exhausted, the request is handed to the dead-letter sink instead of
+    /// being dropped.
+    #[tracing::instrument(skip(self, request))]
+    async fn handle_request_with_retry(&self, request: QueuedRequest) {
+        let (kind, body) = match &request.request {
+            PosthogRequest::CaptureEvent { body } if request.response_tx.is_none() => {
+                (DeadLetterKind::CaptureEvent, body.clone())
+            }
+            PosthogRequest::CaptureBatch { body } if request.response_tx.is_none() => {
+                (DeadLetterKind::CaptureBatch, body.clone())
+            }
+            _ => return self.handle_request(request).await,
+        };
+
+        let attempts = request.attempts;
+        let endpoint = match kind {
+            DeadLetterKind::CaptureEvent => "capture",
+            DeadLetterKind::CaptureBatch => "batch",
+        };
+
+        if self
+            .send_request(Method::POST, endpoint, body.clone())
+            .await
+            .is_err()
+        {
+            if attempts < self.retry.max_retries {
+                metrics::request_retried();
+                self.schedule_retry(kind, body, attempts);
+            } else {
+                metrics::request_failed();
+                self.dead_letter
+                    .write(DeadLetterRequest::from_request(kind, body))
+                    .await;
+            }
+        }
+    }
+
+    /// Sleeps for the backoff delay appropriate to `attempts`, then retries
+    /// the request directly (rather than going back through the worker's
+    /// channel), with its attempt counter incremented. Holds an in-flight
+    /// guard for the whole sleep-then-retry so `flush`/`shutdown` wait for
+    /// it instead of dropping it - including when the worker loop itself
+    /// has already exited on `shutdown`.
+    #[tracing::instrument(skip(self, body))]
+    fn schedule_retry(&self, kind: DeadLetterKind, body: Value, attempts: u32) {
+        let delay = retry_delay(&self.retry, attempts);
+        let worker = self.clone();
+        let guard = self.in_flight.enter();
+
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+
+            let request = match kind {
+                DeadLetterKind::CaptureEvent => PosthogRequest::CaptureEvent { body },
+                DeadLetterKind::CaptureBatch => PosthogRequest::CaptureBatch { body },
+            };
+
+            worker
+                .handle_request_with_retry(QueuedRequest {
+                    request,
+                    response_tx: None,
+                    attempts: attempts + 1,
+                })
+                .await;
+
+            drop(guard);
+        });
+    }
+
+    #[tracing::instrument(skip(self, request))]
     async fn handle_request(&self, request: QueuedRequest) {
         let (method, endpoint, body) = match request.request {
             PosthogRequest::CaptureEvent { body } => (Method::POST, "capture".to_string(), body),
@@ -169,28 +518,201 @@ impl QueueWorkerInner {
         }
     }
 
+    /// Sends `json` to `endpoint`, honoring the rate-limit gate and retrying
+    /// in-place on a 429/503. `request_timeout` only bounds each individual
+    /// HTTP round trip (see [`Self::send_once`]) - it deliberately does not
+    /// bound the wait on [`Self::wait_for_rate_limit_gate`], since a
+    /// `Retry-After` longer than `request_timeout` is a legitimate crawl
+    /// delay, not something that should be treated as a timed-out request.
+    #[tracing::instrument(skip(self, endpoint, json))]
     async fn send_request(
         &self,
         method: Method,
         endpoint: impl Into<String>,
         json: Value,
     ) -> Result<Value, PosthogError> {
+        let endpoint = endpoint.into();
+
+        loop {
+            self.wait_for_rate_limit_gate().await;
+
+            let outcome = tokio::time::timeout(
+                self.request_timeout,
+                self.send_once(method.clone(), &endpoint, &json),
+            )
+            .await
+            .map_err(|_| PosthogError::Timeout)??;
+
+            match outcome {
+                SendOutcome::Success(value) => return Ok(value),
+                SendOutcome::RateLimited => continue,
+            }
+        }
+    }
+
+    /// Performs a single HTTP round trip. Returns
+    /// [`SendOutcome::RateLimited`] (having already raised the gate) instead
+    /// of an error when the response is a 429/503 throttle, so `send_request`
+    /// can retry it without that retry counting against `request_timeout`.
+    async fn send_once(
+        &self,
+        method: Method,
+        endpoint: &str,
+        json: &Value,
+    ) -> Result<SendOutcome, PosthogError> {
         let response = self
             .client
-            .request(method, &format!("{}/{}", self.base_url, endpoint.into()))
+            .request(method, &format!("{}/{}", self.base_url, endpoint))
             .header("Content-Type", "application/json")
             .header("Accept", "application/json")
-            .json(&json)
+            .json(json)
             .send()
             .await
             .map_err(PosthogError::HttpError)?;
 
+        if let Some(delay) = rate_limit_delay(&response) {
+            self.raise_rate_limit_gate(delay);
+            return Ok(SendOutcome::RateLimited);
+        }
+
         if response.status().is_success() {
-            response.json().await.map_err(PosthogError::HttpError)
+            Ok(SendOutcome::Success(
+                response.json().await.map_err(PosthogError::HttpError)?,
+            ))
         } else {
             Err(PosthogError::HttpError(
                 response.error_for_status().unwrap_err(),
             ))
         }
     }
+
+    async fn wait_for_rate_limit_gate(&self) {
+        let not_before = *self.rate_limit_gate.lock().unwrap();
+        let now = Instant::now();
+        if not_before > now {
+            tokio::time::sleep(not_before - now).await;
+        }
+    }
+
+    fn raise_rate_limit_gate(&self, delay: Duration) {
+        let target = Instant::now() + delay;
+        let mut gate = self.rate_limit_gate.lock().unwrap();
+        if target > *gate {
+            *gate = target;
+        }
+    }
+}
+
+/// The crawl-delay style pause PostHog asked for via a 429, or a 503 that
+/// included a `Retry-After` header. A bare 503 with no `Retry-After` is left
+/// as a normal error rather than treated as a throttle.
+fn rate_limit_delay(response: &Response) -> Option<Duration> {
+    let status = response.status();
+    if status != StatusCode::TOO_MANY_REQUESTS && status != StatusCode::SERVICE_UNAVAILABLE {
+        return None;
+    }
+
+    match response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_retry_after)
+    {
+        Some(delay) => Some(delay),
+        None if status == StatusCode::TOO_MANY_REQUESTS => Some(Duration::from_secs(1)),
+        None => None,
+    }
+}
+
+/// Parses a `Retry-After` header value, either delta-seconds or an HTTP-date
+/// (RFC 7231 section 7.1.3).
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let when = httpdate::parse_http_date(value).ok()?;
+    when.duration_since(std::time::SystemTime::now()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+
+    #[test]
+    fn retry_delay_grows_with_attempt_and_respects_jitter_bounds() {
+        let config = RetryConfig {
+            max_retries: 10,
+            base_delay: Duration::from_millis(500),
+        };
+
+        let first = retry_delay(&config, 0);
+        assert!(first >= Duration::from_millis(400) && first <= Duration::from_millis(600));
+
+        let second = retry_delay(&config, 1);
+        assert!(second >= Duration::from_millis(800) && second <= Duration::from_millis(1200));
+    }
+
+    #[test]
+    fn retry_delay_caps_at_max_retry_delay() {
+        let config = RetryConfig {
+            max_retries: 10,
+            base_delay: Duration::from_millis(500),
+        };
+
+        let delay = retry_delay(&config, 16);
+        assert!(delay <= MAX_RETRY_DELAY.mul_f64(1.2));
+    }
+
+    #[test]
+    fn split_by_byte_size_groups_events_under_the_budget() {
+        let events = vec![json!({"a": "x"}), json!({"a": "y"}), json!({"a": "z"})];
+        let one_event_bytes = serde_json::to_vec(&events[0]).unwrap().len();
+
+        let chunks = split_by_byte_size(events.clone(), one_event_bytes * 2);
+
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].len(), 2);
+        assert_eq!(chunks[1].len(), 1);
+        assert_eq!(chunks.iter().map(Vec::len).sum::<usize>(), events.len());
+    }
+
+    #[test]
+    fn split_by_byte_size_keeps_an_oversized_event_as_its_own_chunk() {
+        let small = json!({"a": "x"});
+        let huge = json!({"a": "x".repeat(1000)});
+
+        let chunks = split_by_byte_size(vec![small.clone(), huge.clone()], 10);
+
+        assert_eq!(chunks, vec![vec![small], vec![huge]]);
+    }
+
+    #[test]
+    fn split_by_byte_size_of_no_events_is_empty() {
+        assert!(split_by_byte_size(Vec::new(), 1024).is_empty());
+    }
+
+    #[test]
+    fn parse_retry_after_parses_delta_seconds() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+    }
+
+    #[test]
+    fn parse_retry_after_parses_http_date() {
+        let when = SystemTime::now() + Duration::from_secs(60);
+        let header = httpdate::fmt_http_date(when);
+
+        let delay = parse_retry_after(&header).expect("should parse an HTTP-date");
+        // HTTP-date has one-second resolution, so allow a little slack.
+        assert!(delay >= Duration::from_secs(55) && delay <= Duration::from_secs(65));
+    }
+
+    #[test]
+    fn parse_retry_after_rejects_garbage() {
+        assert_eq!(parse_retry_after("not-a-valid-value"), None);
+    }
 }
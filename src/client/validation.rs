@@ -0,0 +1,92 @@
+use std::collections::HashSet;
+
+use serde_json::Value;
+
+use crate::error::PosthogError;
+
+/// PostHog's own `/capture` ceiling is much higher than this, but a 1MB
+/// event is almost always a sign something went wrong (a raw file, a huge
+/// blob in properties) rather than a legitimate payload.
+pub(crate) const DEFAULT_MAX_EVENT_BYTES: usize = 1024 * 1024;
+
+pub(crate) const DEFAULT_MAX_PROPERTIES: usize = 1000;
+
+pub(crate) const DEFAULT_MAX_PROPERTY_VALUE_BYTES: usize = 64 * 1024;
+
+/// Limits enforced on a `CaptureEvent` payload before it's accepted into the
+/// queue, so a malformed or oversized event is rejected immediately instead
+/// of shipped to PostHog only to be truncated or 413-rejected.
+#[derive(Debug, Clone)]
+pub(crate) struct ValidationConfig {
+    pub(crate) max_event_bytes: usize,
+    pub(crate) max_properties: usize,
+    pub(crate) max_property_value_bytes: usize,
+    pub(crate) allowed_event_names: Option<HashSet<String>>,
+    pub(crate) denied_event_names: Option<HashSet<String>>,
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        Self {
+            max_event_bytes: DEFAULT_MAX_EVENT_BYTES,
+            max_properties: DEFAULT_MAX_PROPERTIES,
+            max_property_value_bytes: DEFAULT_MAX_PROPERTY_VALUE_BYTES,
+            allowed_event_names: None,
+            denied_event_names: None,
+        }
+    }
+}
+
+impl ValidationConfig {
+    /// Checks `event_name`/`body` against every configured limit, returning
+    /// the first violation found.
+    pub(crate) fn validate(&self, event_name: &str, body: &Value) -> Result<(), PosthogError> {
+        if let Some(denied) = &self.denied_event_names {
+            if denied.contains(event_name) {
+                return Err(PosthogError::Validation(format!(
+                    "event \"{event_name}\" is on the configured event-name denylist"
+                )));
+            }
+        }
+
+        if let Some(allowed) = &self.allowed_event_names {
+            if !allowed.contains(event_name) {
+                return Err(PosthogError::Validation(format!(
+                    "event \"{event_name}\" is not on the configured event-name allowlist"
+                )));
+            }
+        }
+
+        let event_bytes = serde_json::to_vec(body).map(|bytes| bytes.len()).unwrap_or(0);
+        if event_bytes > self.max_event_bytes {
+            return Err(PosthogError::Validation(format!(
+                "event \"{event_name}\" is {event_bytes} bytes, exceeding max_event_bytes ({})",
+                self.max_event_bytes
+            )));
+        }
+
+        let Some(properties) = body["properties"].as_object() else {
+            return Ok(());
+        };
+
+        if properties.len() > self.max_properties {
+            return Err(PosthogError::Validation(format!(
+                "event \"{event_name}\" has {} properties, exceeding max_properties ({})",
+                properties.len(),
+                self.max_properties
+            )));
+        }
+
+        for (key, value) in properties {
+            let value_bytes = serde_json::to_vec(value).map(|bytes| bytes.len()).unwrap_or(0);
+            if value_bytes > self.max_property_value_bytes {
+                return Err(PosthogError::Validation(format!(
+                    "property \"{key}\" on event \"{event_name}\" is {value_bytes} bytes, exceeding max_property_value_bytes ({})",
+                    self.max_property_value_bytes
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
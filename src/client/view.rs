@@ -0,0 +1,81 @@
+use async_stream::try_stream;
+use futures_core::Stream;
+use reqwest::Method;
+use serde_json::Value;
+use tokio::sync::oneshot;
+
+use crate::error::PosthogError;
+
+use super::{
+    queue::{PosthogRequest, QueuedRequest},
+    PosthogClient,
+};
+
+impl PosthogClient {
+    /// Follows a PostHog `results`/`next` cursor-paginated GET endpoint,
+    /// yielding individual result items rather than whole pages, so callers
+    /// can use `StreamExt` combinators (e.g. to list every person or early
+    /// access feature) instead of hand-rolling the cursor loop.
+    pub fn paginate<'a>(
+        &'a self,
+        endpoint: impl Into<String>,
+        query: &'a [(&'a str, &'a str)],
+    ) -> impl Stream<Item = Result<Value, PosthogError>> + 'a {
+        let endpoint = endpoint.into();
+
+        try_stream! {
+            let mut next = Some(build_endpoint(&endpoint, query));
+
+            while let Some(current) = next.take() {
+                let page = self.get_page(&current).await?;
+
+                for item in page["results"].as_array().cloned().unwrap_or_default() {
+                    yield item;
+                }
+
+                next = page["next"]
+                    .as_str()
+                    .map(|url| self.relative_endpoint(url));
+            }
+        }
+    }
+
+    async fn get_page(&self, endpoint: &str) -> Result<Value, PosthogError> {
+        let (response_tx, response_rx) = oneshot::channel();
+
+        self.worker.dispatch_request(QueuedRequest {
+            request: PosthogRequest::Other {
+                method: Method::GET,
+                endpoint: endpoint.to_string(),
+                json: Value::Null,
+            },
+            response_tx: Some(response_tx),
+            attempts: 0,
+        });
+
+        response_rx.await.map_err(|_| PosthogError::WorkerGone)?
+    }
+
+    /// `next` comes back from PostHog as an absolute URL; strip the base
+    /// URL back off since `PosthogRequest::Other`'s endpoint gets it
+    /// prefixed again when the request is actually sent.
+    fn relative_endpoint(&self, next: &str) -> String {
+        next.strip_prefix(&self.base_url)
+            .map(|rest| rest.trim_start_matches('/').to_string())
+            .unwrap_or_else(|| next.to_string())
+    }
+}
+
+fn build_endpoint(endpoint: &str, query: &[(&str, &str)]) -> String {
+    if query.is_empty() {
+        return endpoint.to_string();
+    }
+
+    let query_string = query
+        .iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect::<Vec<_>>()
+        .join("&");
+
+    format!("{endpoint}?{query_string}")
+}
@@ -0,0 +1,16 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum PosthogError {
+    #[error("http error: {0}")]
+    HttpError(#[from] reqwest::Error),
+
+    #[error("queue worker is gone")]
+    WorkerGone,
+
+    #[error("request timed out")]
+    Timeout,
+
+    #[error("event validation failed: {0}")]
+    Validation(String),
+}